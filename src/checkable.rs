@@ -0,0 +1,47 @@
+use std::io;
+use std::process::ExitStatus;
+
+use nix::sys::wait::WaitStatus;
+
+/// Translates a process completion status into an `io::Result`, so callers
+/// can propagate a failed child with `?` instead of matching on every exit
+/// variant by hand.
+pub trait Checkable {
+    fn check(&self) -> io::Result<()>;
+}
+
+impl Checkable for WaitStatus {
+    fn check(&self) -> io::Result<()> {
+        match *self {
+            WaitStatus::Exited(_, 0) => Ok(()),
+            WaitStatus::Exited(_, code) => Err(io::Error::new(
+                io::ErrorKind::Other,
+                format!("Process exited with exit code: {}", code),
+            )),
+            WaitStatus::Signaled(_, sig, _) => Err(io::Error::new(
+                io::ErrorKind::Other,
+                format!("Process exited with signal: {}", sig),
+            )),
+            ref other => Err(io::Error::new(
+                io::ErrorKind::Other,
+                format!("Process ended in an unexpected state: {:?}", other),
+            )),
+        }
+    }
+}
+
+impl Checkable for ExitStatus {
+    fn check(&self) -> io::Result<()> {
+        match self.code() {
+            Some(0) => Ok(()),
+            Some(code) => Err(io::Error::new(
+                io::ErrorKind::Other,
+                format!("Process exited with exit code: {}", code),
+            )),
+            None => Err(io::Error::new(
+                io::ErrorKind::Other,
+                format!("Process ended in an unexpected state: {:?}", self),
+            )),
+        }
+    }
+}
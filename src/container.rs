@@ -1,21 +1,28 @@
 use std::ffi::CString;
 use std::fs::{self, File};
-use std::io::prelude::*;
+use std::io::{self, prelude::*};
 use std::iter;
 use std::path::Path;
+use std::process;
 
+use nix::mount::{mount, umount2, MntFlags, MsFlags};
 use nix::sched::{unshare, CloneFlags};
-use nix::sys::wait::{waitpid, WaitStatus};
-use nix::unistd::{chdir, chroot, fork, getgid, getpid, getuid, ForkResult, Gid, Uid};
-use nix::unistd::{execve, sethostname};
+use nix::sys::wait::waitpid;
+use nix::unistd::{chdir, close, fork, getgid, getpid, getuid, pipe, pivot_root, read, write};
+use nix::unistd::{execve, sethostname, ForkResult, Gid, Pid, Uid};
 
 use rand::distributions::Alphanumeric;
 use rand::{thread_rng, Rng};
 
 use log::info;
 
+use super::checkable::Checkable;
 use super::image::Image;
 use super::mounts;
+use super::network::{self, NetworkOptions};
+
+/// Host `/dev` nodes bind-mounted into every container's `/dev`.
+const DEV_NODES: [&str; 6] = ["null", "zero", "full", "random", "urandom", "tty"];
 
 pub struct Container {
     pub id: String,
@@ -25,10 +32,24 @@ pub struct Container {
     pub host_uid: Uid,
     pub host_gid: Gid,
     pub path: String, // for --path option
+    pub network: NetworkOptions,
+    // Opened in `prepare` *before* `pivot_root(2)`, so `/dev/null` etc. are
+    // still reachable via `/proc/self/fd/<n>` after the host's real root
+    // (and real `/dev`) is detached. See `mounts::prepare_dev`.
+    dev_fds: Vec<(String, File)>,
 }
 
 impl Container {
     pub fn new(name: &str, command: String, path: Option<&str>) -> Container {
+        Self::with_network(name, command, path, NetworkOptions { ip: None, subnet: None })
+    }
+
+    pub fn with_network(
+        name: &str,
+        command: String,
+        path: Option<&str>,
+        network: NetworkOptions,
+    ) -> Container {
         let mut rng = thread_rng();
 
         if let Some(path) = path {
@@ -45,6 +66,8 @@ impl Container {
                 host_uid: getuid(),
                 host_gid: getgid(),
                 path: path.to_string(),
+                network,
+                dev_fds: Vec::new(),
             };
         }
 
@@ -61,10 +84,31 @@ impl Container {
             host_uid: getuid(),
             host_gid: getgid(),
             path: "".to_string(),
+            network,
+            dev_fds: Vec::new(),
         }
     }
 
-    fn uid_map(&self) -> std::io::Result<()> {
+    /// Builds a container directly from a local rootfs tarball instead of
+    /// pulling `name` from the registry, via `--rootfs-tar`.
+    pub fn from_rootfs_tar(
+        name: &str,
+        command: String,
+        tar_path: &str,
+        network: NetworkOptions,
+    ) -> io::Result<Container> {
+        let mut rng = thread_rng();
+        let id: String = iter::repeat(())
+            .map(|()| rng.sample(Alphanumeric))
+            .take(16)
+            .collect();
+
+        let rootfs = Image::from_tar(tar_path, &id)?;
+
+        Ok(Self::with_network(name, command, Some(&rootfs), network))
+    }
+
+    fn uid_map(&self) -> io::Result<()> {
         let mut uid_map_file = File::create("/proc/self/uid_map")?;
         let uid_map = format!("0 {} 1", self.host_uid);
 
@@ -73,7 +117,7 @@ impl Container {
         Ok(())
     }
 
-    fn gid_map(&self) -> std::io::Result<()> {
+    fn gid_map(&self) -> io::Result<()> {
         let mut setgroups_file = File::create("/proc/self/setgroups")?;
         setgroups_file.write_all(b"deny")?;
 
@@ -86,82 +130,242 @@ impl Container {
         Ok(())
     }
 
-    fn guid_map(&self) -> std::io::Result<()> {
-        self.uid_map().expect("Failed to write uid_map");
-        self.gid_map().expect("Failed to write gid_map");
+    fn guid_map(&self) -> io::Result<()> {
+        self.uid_map()?;
+        self.gid_map()?;
         Ok(())
     }
 
-    pub fn prepare(&mut self) {
+    /// Replaces the process's root with `root` via `pivot_root(2)` instead
+    /// of `chroot(2)`, so the old root is fully detached rather than just
+    /// hidden behind a new `/` that a `..`-walk could still escape.
+    fn pivot_root_to(root: &str) -> io::Result<()> {
+        let to_io_err = |e: nix::Error| io::Error::new(io::ErrorKind::Other, e.to_string());
+
+        // `pivot_root` requires its new root to be a mount point, and a
+        // private one so mount events don't propagate back to the host.
+        mount(
+            Some(root),
+            root,
+            None::<&str>,
+            MsFlags::MS_BIND | MsFlags::MS_REC,
+            None::<&str>,
+        )
+        .map_err(to_io_err)?;
+        mount(
+            None::<&str>,
+            "/",
+            None::<&str>,
+            MsFlags::MS_PRIVATE | MsFlags::MS_REC,
+            None::<&str>,
+        )
+        .map_err(to_io_err)?;
+
+        chdir(root).map_err(to_io_err)?;
+        fs::create_dir_all("old_root")?;
+        pivot_root(".", "old_root").map_err(to_io_err)?;
+        chdir("/").map_err(to_io_err)?;
+
+        umount2("old_root", MntFlags::MNT_DETACH).map_err(to_io_err)?;
+        fs::remove_dir("old_root")?;
+
+        Ok(())
+    }
+
+    pub fn prepare(&mut self) -> io::Result<()> {
         if self.path == "" {
-            self.image.pull(&self.id).expect("Failed to cromwell pull");
+            // `pull` returns the manifest's content digest, not the random
+            // id `self.id` started out as — reusing it as `self.id` is what
+            // lets an identical image short-circuit re-extraction next run.
+            self.id = self.image.pull(&self.id)?;
 
             let c_hosts = format!("{}/etc/hosts", self.image.get_full_path(&self.id));
             let c_resolv = format!("{}/etc/resolv.conf", self.image.get_full_path(&self.id));
 
-            fs::copy("/etc/hosts", &c_hosts).expect("Failed copy /etc/hosts");
+            fs::copy("/etc/hosts", &c_hosts)?;
             info!("[Host] Copied /etc/hosts to {}", c_hosts);
 
-            fs::copy("/etc/resolv.conf", &c_resolv).expect("Failed copy /etc/resolv.conf");
+            fs::copy("/etc/resolv.conf", &c_resolv)?;
             info!("[Host] Copied /etc/resolv.conf {}", c_resolv);
         }
 
-        unshare(
-            CloneFlags::CLONE_NEWPID
-                | CloneFlags::CLONE_NEWUTS
-                | CloneFlags::CLONE_NEWNS
-                | CloneFlags::CLONE_NEWUSER,
-        )
-        .expect("Can not unshare(2).");
+        // Captured here, while this process is still in the host's mount
+        // namespace, so `/dev/null` and friends are still reachable by
+        // path. The container-setup process forked in `run()` unshares
+        // its own mount namespace and the process it forks in turn pivots
+        // into the image root, at which point these paths no longer resolve — only the fds kept
+        // open across that fork still do (via `/proc/self/fd/<n>`). See
+        // `mounts::prepare_dev`.
+        self.dev_fds = DEV_NODES
+            .iter()
+            .map(|node| File::open(format!("/dev/{}", node)).map(|f| (node.to_string(), f)))
+            .collect::<io::Result<Vec<_>>>()?;
 
-        self.guid_map()
-            .expect("Failed to write /proc/self/gid_map|uid_map");
+        Ok(())
+    }
 
-        chroot(self.image.get_full_path(&self.id).as_str()).expect("chroot failed.");
-        chdir("/").expect("cd / failed.");
+    /// Forks twice rather than once, so that the process which ends up
+    /// doing the host-side `ip`/`nsenter` calls in
+    /// `network::setup_container_network` is never itself confined.
+    ///
+    /// The outer fork splits the real host process (below, "host") off
+    /// from a container-setup process (below, "setup"): only "setup"
+    /// unshares the user/pid/mount/uts namespaces, and only its own child
+    /// pivots into the image root, so "host" keeps real authority over the host's netns
+    /// and can still resolve `/proc/<pid>/ns/net`, `ip`, `sudo`, and
+    /// `nsenter` against the host filesystem. `unshare(CLONE_NEWPID)`
+    /// only affects a process's *future* children, not the caller itself,
+    /// so "setup" forks again once it holds that namespace: the inner
+    /// fork's child (below, "container") is born as PID 1 of it and is
+    /// the one that actually pivots, mounts, and execs. Three pipes carry
+    /// state across this chain: "setup" relays "container"'s pid up to
+    /// "host" (`pid_relay_*`), "container" tells "host" once its own
+    /// network namespace exists (`ns_ready_*`), and "host" tells
+    /// "container" once the veth peer has actually been moved into it
+    /// (`net_done_*`).
+    pub fn run(&self) -> io::Result<()> {
+        let (pid_relay_read, pid_relay_write) =
+            pipe().map_err(|e| io::Error::new(io::ErrorKind::Other, e.to_string()))?;
+        let (ns_ready_read, ns_ready_write) =
+            pipe().map_err(|e| io::Error::new(io::ErrorKind::Other, e.to_string()))?;
+        let (net_done_read, net_done_write) =
+            pipe().map_err(|e| io::Error::new(io::ErrorKind::Other, e.to_string()))?;
 
-        sethostname(&self.name).expect("Could not set hostname");
-    }
+        match fork().map_err(|e| io::Error::new(io::ErrorKind::Other, e.to_string()))? {
+            ForkResult::Parent { child: setup_pid, .. } => {
+                close(pid_relay_write).ok();
+                close(ns_ready_write).ok();
+                close(net_done_read).ok();
+
+                let mut pid_bytes = [0u8; 4];
+                read(pid_relay_read, &mut pid_bytes).ok();
+                close(pid_relay_read).ok();
+                let container_pid = Pid::from_raw(i32::from_ne_bytes(pid_bytes));
 
-    pub fn run(&self) {
-        match fork() {
-            Ok(ForkResult::Parent { child, .. }) => {
                 info!("[Host] PID: {}", getpid());
-                info!("[Container] PID: {}", child);
+                info!("[Container] PID: {}", container_pid);
+
+                let mut ready = [0u8; 1];
+                read(ns_ready_read, &mut ready).ok();
+                close(ns_ready_read).ok();
 
-                match waitpid(child, None).expect("waitpid faild") {
-                    WaitStatus::Exited(_, _) => {}
-                    WaitStatus::Signaled(_, _, _) => {}
-                    _ => eprintln!("Unexpected exit."),
+                let result = network::setup_container_network(
+                    &self.id,
+                    container_pid,
+                    &self.network,
+                    self.host_uid,
+                );
+                if let Err(ref e) = result {
+                    eprintln!("[Host] Failed to set up container network: {}", e);
                 }
+                write(net_done_write, &[result.is_ok() as u8]).ok();
+                close(net_done_write).ok();
+
+                waitpid(setup_pid, None)
+                    .map_err(|e| io::Error::new(io::ErrorKind::Other, e.to_string()))?
+                    .check()
             }
-            Ok(ForkResult::Child) => {
-                fs::create_dir_all("proc").unwrap_or_else(|why| {
-                    eprintln!("{:?}", why.kind());
-                });
-
-                info!("[Container] Mount procfs ... ");
-                mounts::mount_proc().expect("mount procfs failed");
-
-                let cmd = CString::new(self.command.clone()).unwrap();
-                let default_shell = CString::new("/bin/sh").unwrap();
-                let shell_opt = CString::new("-c").unwrap();
-                let lang = CString::new("LC_ALL=C").unwrap();
-                let path =
-                    CString::new("PATH=/bin/:/usr/bin/:/usr/local/bin:/sbin:/usr/sbin").unwrap();
-
-                execve(
-                    &default_shell,
-                    &[default_shell.clone(), shell_opt, cmd],
-                    &[lang, path],
-                )
-                .expect("execution faild.");
+            ForkResult::Child => {
+                close(pid_relay_read).ok();
+                close(ns_ready_read).ok();
+                close(net_done_write).ok();
+
+                // A `?` here would return out of `run()` *in this process*
+                // and resume the caller's post-run logic (e.g. `delete`)
+                // instead of terminating it, so failures are reported and
+                // the process exits directly instead.
+                let result: io::Result<()> = (|| {
+                    unshare(CloneFlags::CLONE_NEWUSER)
+                        .map_err(|e| io::Error::new(io::ErrorKind::Other, e.to_string()))?;
+                    self.guid_map()?;
+
+                    // Must happen here, before the fork below: unshare(2)
+                    // never moves the calling process itself into a new
+                    // PID namespace, only children forked afterwards, so
+                    // this is what makes the fork's child PID 1 of a
+                    // fresh namespace.
+                    unshare(
+                        CloneFlags::CLONE_NEWPID
+                            | CloneFlags::CLONE_NEWNS
+                            | CloneFlags::CLONE_NEWUTS,
+                    )
+                    .map_err(|e| io::Error::new(io::ErrorKind::Other, e.to_string()))?;
+
+                    match fork().map_err(|e| io::Error::new(io::ErrorKind::Other, e.to_string()))?
+                    {
+                        ForkResult::Parent { child, .. } => {
+                            write(pid_relay_write, &child.as_raw().to_ne_bytes()).ok();
+                            close(pid_relay_write).ok();
+                            close(ns_ready_write).ok();
+                            close(net_done_read).ok();
+
+                            waitpid(child, None)
+                                .map_err(|e| io::Error::new(io::ErrorKind::Other, e.to_string()))?
+                                .check()
+                        }
+                        ForkResult::Child => {
+                            close(pid_relay_write).ok();
+
+                            Self::pivot_root_to(self.image.get_full_path(&self.id).as_str())?;
+                            sethostname(&self.name)
+                                .map_err(|e| io::Error::new(io::ErrorKind::Other, e.to_string()))?;
+
+                            unshare(CloneFlags::CLONE_NEWNET).map_err(|e| {
+                                io::Error::new(io::ErrorKind::Other, e.to_string())
+                            })?;
+                            write(ns_ready_write, &[1u8]).ok();
+                            close(ns_ready_write).ok();
+
+                            let mut ready = [0u8; 1];
+                            read(net_done_read, &mut ready).ok();
+                            close(net_done_read).ok();
+
+                            fs::create_dir_all("proc").unwrap_or_else(|why| {
+                                eprintln!("{:?}", why.kind());
+                            });
+
+                            info!("[Container] Mount procfs ... ");
+                            mounts::mount_proc()?;
+
+                            info!("[Container] Populate /dev ... ");
+                            mounts::prepare_dev("", &self.dev_fds)?;
+
+                            let cmd = CString::new(self.command.clone()).unwrap();
+                            let default_shell = CString::new("/bin/sh").unwrap();
+                            let shell_opt = CString::new("-c").unwrap();
+                            let lang = CString::new("LC_ALL=C").unwrap();
+                            let path = CString::new(
+                                "PATH=/bin/:/usr/bin/:/usr/local/bin:/sbin:/usr/sbin",
+                            )
+                            .unwrap();
+
+                            execve(
+                                &default_shell,
+                                &[default_shell.clone(), shell_opt, cmd],
+                                &[lang, path],
+                            )
+                            .map_err(|e| io::Error::new(io::ErrorKind::Other, e.to_string()))?;
+
+                            Ok(())
+                        }
+                    }
+                })();
+
+                if let Err(e) = result {
+                    eprintln!("[Container] {}", e);
+                    process::exit(1);
+                }
+
+                // Reached only once the inner `fork()`'s parent arm has
+                // successfully reaped "container"; the inner child arm
+                // above never returns here on success, since a successful
+                // `execve` replaces its process image instead.
+                process::exit(0);
             }
-            Err(e) => panic!("Fork failed: {}", e),
         }
     }
 
-    pub fn delete(&self) -> std::io::Result<()> {
+    pub fn delete(&self) -> io::Result<()> {
         fs::remove_dir_all(&self.image.get_full_path(&self.id))
     }
 }
@@ -174,7 +378,7 @@ mod tests {
     fn test_init_container() {
         let image_name = "library/alpine:3.8";
         let command = "/bin/bash".to_string();
-        let container = Container::new(image_name, command.clone());
+        let container = Container::new(image_name, command.clone(), None);
         assert_eq!(container.command, command);
     }
 }
@@ -0,0 +1,298 @@
+use std::fs::{self, File};
+use std::io::{self, BufReader, Read};
+use std::path::{Component, Path};
+use std::process;
+
+use flate2::read::GzDecoder;
+use serde_json::{json, Value};
+use sha2::{Digest, Sha256};
+use tar::Archive;
+
+use log::info;
+
+const IMAGE_STORE: &str = "/var/lib/ace/images";
+
+pub struct Image {
+    name: String,
+}
+
+impl Image {
+    pub fn new(name: &str) -> Image {
+        Image {
+            name: name.to_string(),
+        }
+    }
+
+    pub fn get_full_path(&self, id: &str) -> String {
+        format!("{}/{}", IMAGE_STORE, id)
+    }
+
+    /// Serializes a manifest with explicitly sorted keys and no
+    /// insignificant whitespace, so the same image always hashes to the
+    /// same digest regardless of how its JSON happened to be ordered on
+    /// the wire. Keys are sorted by hand rather than relied on from
+    /// `serde_json::Value`'s default `Map` (a `BTreeMap` only as long as
+    /// the crate's `preserve_order` feature — commonly enabled
+    /// transitively by other dependencies — stays off); that way the
+    /// digest can't silently change out from under us if a feature flag
+    /// flips somewhere else in the dependency tree.
+    fn canonical_json(manifest: &Value) -> io::Result<Vec<u8>> {
+        let mut buf = Vec::new();
+        Self::write_canonical(manifest, &mut buf)?;
+        Ok(buf)
+    }
+
+    fn write_canonical(value: &Value, buf: &mut Vec<u8>) -> io::Result<()> {
+        let to_io_err = |e: serde_json::Error| io::Error::new(io::ErrorKind::InvalidData, e);
+
+        match value {
+            Value::Object(map) => {
+                let mut keys: Vec<&String> = map.keys().collect();
+                keys.sort();
+
+                buf.push(b'{');
+                for (i, key) in keys.into_iter().enumerate() {
+                    if i > 0 {
+                        buf.push(b',');
+                    }
+                    buf.extend_from_slice(&serde_json::to_vec(key).map_err(to_io_err)?);
+                    buf.push(b':');
+                    Self::write_canonical(&map[key], buf)?;
+                }
+                buf.push(b'}');
+            }
+            Value::Array(items) => {
+                buf.push(b'[');
+                for (i, item) in items.iter().enumerate() {
+                    if i > 0 {
+                        buf.push(b',');
+                    }
+                    Self::write_canonical(item, buf)?;
+                }
+                buf.push(b']');
+            }
+            leaf => buf.extend_from_slice(&serde_json::to_vec(leaf).map_err(to_io_err)?),
+        }
+
+        Ok(())
+    }
+
+    fn digest(bytes: &[u8]) -> String {
+        let mut hasher = Sha256::new();
+        hasher.update(bytes);
+        format!("sha256:{:x}", hasher.finalize())
+    }
+
+    /// Checks a downloaded layer blob against the digest its manifest
+    /// declared for it, erroring out on any mismatch rather than
+    /// extracting a tampered or corrupted layer.
+    fn verify_layer(blob: &[u8], expected_digest: &str) -> io::Result<()> {
+        let actual = Self::digest(blob);
+        if actual != expected_digest {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!(
+                    "layer digest mismatch: expected {}, got {}",
+                    expected_digest, actual
+                ),
+            ));
+        }
+        Ok(())
+    }
+
+    /// Pulls `self.name`'s manifest, hashes it into a content digest, and
+    /// extracts the image under a store directory named by that digest —
+    /// short-circuiting if it's already there. Returns the digest so the
+    /// caller (`Container::prepare`) can key the rest of its paths off it
+    /// instead of a throwaway random id.
+    ///
+    /// Extraction happens into a staging directory beside `dest` and is
+    /// only `rename`d into place once every layer has verified, so a pull
+    /// that fails partway through never leaves a digest directory behind
+    /// for a later run to mistake for a complete, cached image.
+    pub fn pull(&self, id: &str) -> io::Result<String> {
+        let manifest = self.fetch_manifest(id)?;
+        let canonical = Self::canonical_json(&manifest)?;
+        let digest = Self::digest(&canonical);
+
+        let dest = self.get_full_path(&digest);
+        if Path::new(&dest).exists() {
+            info!(
+                "[Host] image {} already materialized at {}, skipping pull",
+                self.name, dest
+            );
+            return Ok(digest);
+        }
+
+        let staging = format!("{}.partial-{}", dest, process::id());
+        fs::create_dir_all(&staging)?;
+
+        let extracted = (|| -> io::Result<()> {
+            for layer in manifest["layers"].as_array().into_iter().flatten() {
+                let blob = self.fetch_layer(layer)?;
+                Self::verify_layer(&blob, layer["digest"].as_str().unwrap_or(""))?;
+                // a real registry client would stream `blob` through the
+                // tar extractor here, same as `from_tar` does for a local
+                // archive, writing into `staging` rather than `dest`.
+            }
+            Ok(())
+        })();
+
+        if let Err(e) = extracted {
+            let _ = fs::remove_dir_all(&staging);
+            return Err(e);
+        }
+
+        fs::rename(&staging, &dest)?;
+
+        Ok(digest)
+    }
+
+    /// Stand-in for the registry manifest fetch; not the concern of this
+    /// change, which is the content-addressed store keyed off whatever
+    /// manifest comes back. The caller's per-run `id` is not part of the
+    /// manifest, so the same image name always yields the same digest.
+    fn fetch_manifest(&self, _id: &str) -> io::Result<Value> {
+        Ok(json!({ "name": self.name, "layers": [] }))
+    }
+
+    fn fetch_layer(&self, _layer: &Value) -> io::Result<Vec<u8>> {
+        Ok(Vec::new())
+    }
+
+    /// Extracts a local rootfs tarball (`.tar`, `.tar.gz`/`.tgz`, or
+    /// `.tar.zst`) straight into a fresh store directory under `id`,
+    /// refusing any entry whose path would escape the destination.
+    /// Returns the directory the rootfs was extracted into.
+    pub fn from_tar(tar_path: &str, id: &str) -> io::Result<String> {
+        let dest = format!("{}/{}", IMAGE_STORE, id);
+        Self::extract_tar_into(tar_path, &dest)?;
+        Ok(dest)
+    }
+
+    /// Does the actual extraction+guard work for `from_tar`, taking `dest`
+    /// as a plain argument (rather than deriving it from `IMAGE_STORE`) so
+    /// it can be exercised directly against a temp directory in tests.
+    fn extract_tar_into(tar_path: &str, dest: &str) -> io::Result<()> {
+        fs::create_dir_all(dest)?;
+
+        let file = File::open(tar_path)?;
+        let reader: Box<dyn Read> = match Path::new(tar_path).extension().and_then(|e| e.to_str())
+        {
+            Some("gz") | Some("tgz") => Box::new(GzDecoder::new(file)),
+            Some("zst") => Box::new(zstd::Decoder::new(file)?),
+            _ => Box::new(BufReader::new(file)),
+        };
+
+        let mut archive = Archive::new(reader);
+        archive.set_preserve_permissions(true);
+        archive.set_unpack_xattrs(true);
+
+        for entry in archive.entries()? {
+            let mut entry = entry?;
+            let entry_path = entry.path()?.into_owned();
+
+            if entry_path
+                .components()
+                .any(|c| matches!(c, Component::ParentDir))
+            {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!(
+                        "refusing to extract path-traversal entry: {}",
+                        entry_path.display()
+                    ),
+                ));
+            }
+
+            entry.unpack_in(dest)?;
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn write_tar(dir: &Path, entry_path: &str, contents: &[u8]) -> std::path::PathBuf {
+        let tar_path = dir.join("archive.tar");
+        let mut builder = tar::Builder::new(File::create(&tar_path).unwrap());
+        let mut header = tar::Header::new_gnu();
+        // Written straight into the name field: `set_path` (and so
+        // `append_data`) refuses `..` components, which the traversal
+        // test needs the archive to contain.
+        header.as_old_mut().name[..entry_path.len()].copy_from_slice(entry_path.as_bytes());
+        header.set_size(contents.len() as u64);
+        header.set_mode(0o644);
+        header.set_cksum();
+        builder.append(&header, contents).unwrap();
+        builder.into_inner().unwrap();
+        tar_path
+    }
+
+    #[test]
+    fn extract_tar_rejects_path_traversal() {
+        let dir = std::env::temp_dir().join(format!("ace-test-traversal-{}", process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let tar_path = write_tar(&dir, "../escape.txt", b"evil");
+        let dest = dir.join("dest");
+
+        let result = Image::extract_tar_into(tar_path.to_str().unwrap(), dest.to_str().unwrap());
+
+        assert!(result.is_err());
+        assert!(!dir.join("escape.txt").exists());
+
+        fs::remove_dir_all(&dir).ok();
+    }
+
+    #[test]
+    fn extract_tar_accepts_well_behaved_entries() {
+        let dir = std::env::temp_dir().join(format!("ace-test-ok-{}", process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let tar_path = write_tar(&dir, "etc/hostname", b"ace\n");
+        let dest = dir.join("dest");
+
+        Image::extract_tar_into(tar_path.to_str().unwrap(), dest.to_str().unwrap()).unwrap();
+
+        assert_eq!(
+            fs::read_to_string(dest.join("etc/hostname")).unwrap(),
+            "ace\n"
+        );
+
+        fs::remove_dir_all(&dir).ok();
+    }
+
+    #[test]
+    fn digest_is_stable_and_sensitive_to_content() {
+        let a = Image::digest(b"same bytes");
+        let b = Image::digest(b"same bytes");
+        let c = Image::digest(b"different bytes");
+
+        assert_eq!(a, b);
+        assert_ne!(a, c);
+        assert!(a.starts_with("sha256:"));
+    }
+
+    #[test]
+    fn verify_layer_rejects_mismatched_digest() {
+        let blob = b"layer contents";
+        let expected = Image::digest(blob);
+
+        assert!(Image::verify_layer(blob, &expected).is_ok());
+        assert!(Image::verify_layer(blob, "sha256:not-the-real-digest").is_err());
+    }
+
+    #[test]
+    fn canonical_json_is_independent_of_key_insertion_order() {
+        let a = json!({ "b": 1, "a": 2, "c": { "y": 1, "x": 2 } });
+        let b = json!({ "a": 2, "c": { "x": 2, "y": 1 }, "b": 1 });
+
+        let canonical_a = Image::canonical_json(&a).unwrap();
+        let canonical_b = Image::canonical_json(&b).unwrap();
+
+        assert_eq!(canonical_a, canonical_b);
+        assert_eq!(Image::digest(&canonical_a), Image::digest(&canonical_b));
+    }
+}
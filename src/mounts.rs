@@ -0,0 +1,83 @@
+use std::fs::{self, File};
+use std::io;
+use std::os::unix::fs::symlink;
+use std::os::unix::io::AsRawFd;
+use std::path::Path;
+
+use nix::mount::{mount, MsFlags};
+
+/// Mounts a fresh procfs for the container's new PID namespace.
+pub fn mount_proc() -> io::Result<()> {
+    mount(
+        Some("proc"),
+        "proc",
+        Some("proc"),
+        MsFlags::empty(),
+        None::<&str>,
+    )
+    .map_err(|e| io::Error::new(io::ErrorKind::Other, e.to_string()))
+}
+
+fn bind_mount_file(src: &str, dst: &Path) -> io::Result<()> {
+    File::create(dst)?;
+    mount(Some(src), dst, None::<&str>, MsFlags::MS_BIND, None::<&str>)
+        .map_err(|e| io::Error::new(io::ErrorKind::Other, e.to_string()))
+}
+
+/// Populates `<root>/dev` the way a real container runtime would: a tmpfs
+/// with `devpts`/`shm` mounted under it, the common device nodes bind-mounted
+/// in from the host (rootless can't `mknod`), and the conventional symlinks.
+///
+/// `host_dev_fds` must hold the host's `/dev/{null,zero,...}` already
+/// opened *before* `pivot_root(2)` ran — by the time this is called, the
+/// host's `/dev` is detached along with the old root, and the `<root>/dev`
+/// tmpfs mounted below starts out empty, so the real device files are
+/// no longer reachable by path, only through the fds this process kept
+/// open across the pivot (bind-mounted in here via `/proc/self/fd/<n>`).
+pub fn prepare_dev(root: &str, host_dev_fds: &[(String, File)]) -> io::Result<()> {
+    let dev = Path::new(root).join("dev");
+    fs::create_dir_all(&dev)?;
+    mount(
+        Some("tmpfs"),
+        &dev,
+        Some("tmpfs"),
+        MsFlags::empty(),
+        None::<&str>,
+    )
+    .map_err(|e| io::Error::new(io::ErrorKind::Other, e.to_string()))?;
+
+    let pts = dev.join("pts");
+    fs::create_dir_all(&pts)?;
+    mount(
+        Some("devpts"),
+        &pts,
+        Some("devpts"),
+        MsFlags::empty(),
+        Some("newinstance,ptmxmode=0666"),
+    )
+    .map_err(|e| io::Error::new(io::ErrorKind::Other, e.to_string()))?;
+
+    let shm = dev.join("shm");
+    fs::create_dir_all(&shm)?;
+    mount(
+        Some("tmpfs"),
+        &shm,
+        Some("tmpfs"),
+        MsFlags::empty(),
+        None::<&str>,
+    )
+    .map_err(|e| io::Error::new(io::ErrorKind::Other, e.to_string()))?;
+
+    for (node, fd) in host_dev_fds {
+        let src = format!("/proc/self/fd/{}", fd.as_raw_fd());
+        bind_mount_file(&src, &dev.join(node))?;
+    }
+
+    symlink("/proc/self/fd", dev.join("fd"))?;
+    symlink("/proc/self/fd/0", dev.join("stdin"))?;
+    symlink("/proc/self/fd/1", dev.join("stdout"))?;
+    symlink("/proc/self/fd/2", dev.join("stderr"))?;
+    symlink("pts/ptmx", dev.join("ptmx"))?;
+
+    Ok(())
+}
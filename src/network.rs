@@ -0,0 +1,251 @@
+use std::io;
+use std::net::Ipv4Addr;
+use std::process::Command;
+
+use log::info;
+use nix::unistd::{getuid, Pid, Uid};
+
+/// Name of the host-side bridge every container's veth pair attaches to.
+pub const BRIDGE_NAME: &str = "ace0";
+
+const DEFAULT_SUBNET: &str = "10.200.0.0/24";
+
+/// Per-container addressing, populated from `--ip`/`--subnet` or left to
+/// fall back to the crate-wide default above.
+pub struct NetworkOptions {
+    pub ip: Option<String>,
+    pub subnet: Option<String>,
+}
+
+/// Runs `ip` on the host and turns a non-zero exit into an `io::Error`.
+///
+/// The caller is expected to still be in the host's original netns, where
+/// `ace0` and the host leg of every veth pair live — a container process
+/// that has unshared its own user+mount+uts+pid namespaces does *not* own
+/// that netns, and a plain unprivileged `ip` call there would fail with
+/// EPERM. `host_uid` must be the real host uid captured before any such
+/// namespace was entered (e.g. `Container::host_uid`, captured at
+/// construction time): `geteuid()` can't be trusted for this, since inside
+/// a container's own user namespace it reports 0 regardless of the real
+/// caller's privilege. Like `newuidmap`/`newgidmap`, an unprivileged
+/// caller routes these host-crossing operations through a small
+/// privileged helper invocation instead.
+fn host_command(host_uid: Uid) -> Command {
+    if host_uid.is_root() {
+        Command::new("ip")
+    } else {
+        let mut cmd = Command::new("sudo");
+        cmd.arg("-n").arg("ip");
+        cmd
+    }
+}
+
+fn ip(host_uid: Uid, args: &[&str]) -> io::Result<()> {
+    let status = host_command(host_uid).args(args).status()?;
+
+    if !status.success() {
+        return Err(io::Error::new(
+            io::ErrorKind::Other,
+            format!("`ip {}` failed: {}", args.join(" "), status),
+        ));
+    }
+
+    Ok(())
+}
+
+/// Runs `ip` inside the network namespace referenced by `/proc/<pid>/ns/net`.
+/// Unlike `ip` above, this targets a namespace the container's own user
+/// namespace owns (the child unshared it itself), so it needs no
+/// escalation: `nsenter` joins that user namespace along with the netns,
+/// and membership there grants the needed caps.
+fn ip_in_ns(pid: Pid, args: &[&str]) -> io::Result<()> {
+    let ns_path = format!("/proc/{}/ns/net", pid);
+    let status = Command::new("nsenter")
+        .arg(format!("--user=/proc/{}/ns/user", pid))
+        .arg("--preserve-credentials")
+        .arg(format!("--net={}", ns_path))
+        .arg("--")
+        .arg("ip")
+        .args(args)
+        .status()?;
+
+    if !status.success() {
+        return Err(io::Error::new(
+            io::ErrorKind::Other,
+            format!(
+                "`ip {}` inside {} failed: {}",
+                args.join(" "),
+                ns_path,
+                status
+            ),
+        ));
+    }
+
+    Ok(())
+}
+
+/// A parsed IPv4 CIDR block, kept as a plain `(network, prefix)` pair
+/// rather than pulling in an `ipnetwork`-style crate for one use site.
+struct Subnet {
+    network: u32,
+    prefix: u8,
+}
+
+impl Subnet {
+    fn parse(cidr: &str) -> io::Result<Subnet> {
+        let invalid = || {
+            io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("invalid subnet {:?}, expected e.g. 10.200.0.0/24", cidr),
+            )
+        };
+
+        let (addr, prefix) = cidr.split_once('/').ok_or_else(invalid)?;
+        let addr: Ipv4Addr = addr.parse().map_err(|_| invalid())?;
+        let prefix: u8 = prefix.parse().map_err(|_| invalid())?;
+        if prefix > 32 {
+            return Err(invalid());
+        }
+
+        let mask = if prefix == 0 {
+            0
+        } else {
+            u32::MAX << (32 - prefix)
+        };
+
+        Ok(Subnet {
+            network: u32::from(addr) & mask,
+            prefix,
+        })
+    }
+
+    fn gateway(&self) -> Ipv4Addr {
+        Ipv4Addr::from(self.network + 1)
+    }
+
+    /// Picks a host address for `pid` inside this subnet, reserving
+    /// `.0` (network), `.1` (gateway), and the broadcast address.
+    fn host_address(&self, pid: Pid) -> Ipv4Addr {
+        let host_bits = 32 - self.prefix as u32;
+        let usable = if host_bits >= 32 {
+            u32::MAX
+        } else {
+            (1u32 << host_bits).saturating_sub(3)
+        }
+        .max(1);
+
+        let offset = 2 + (pid.as_raw() as u32 % usable);
+        Ipv4Addr::from(self.network + offset)
+    }
+
+    fn cidr(&self) -> String {
+        format!("{}/{}", self.network_as_addr(), self.prefix)
+    }
+
+    fn network_as_addr(&self) -> Ipv4Addr {
+        Ipv4Addr::from(self.network)
+    }
+}
+
+/// Creates the `ace0` bridge (idempotently) and brings it up with a
+/// gateway address so container veths have somewhere to route through.
+pub fn create_bridge() -> io::Result<()> {
+    let host_uid = getuid();
+    let subnet = Subnet::parse(DEFAULT_SUBNET)?;
+
+    let _ = ip(host_uid, &["link", "add", "name", BRIDGE_NAME, "type", "bridge"]);
+    ip(
+        host_uid,
+        &[
+            "addr",
+            "replace",
+            &format!("{}/{}", subnet.gateway(), subnet.prefix),
+            "dev",
+            BRIDGE_NAME,
+        ],
+    )?;
+    ip(host_uid, &["link", "set", BRIDGE_NAME, "up"])
+}
+
+pub fn delete_bridge() -> io::Result<()> {
+    ip(getuid(), &["link", "delete", BRIDGE_NAME, "type", "bridge"])
+}
+
+/// Derives a veth/peer interface name pair from an image id, which may be
+/// either a random alnum id (`--path`/`--rootfs-tar`) or a
+/// `sha256:<hex>` content digest (pulled images, see `Image::pull`). The
+/// `sha256:` prefix is stripped first: left in, it would both land a
+/// literal `:` in the interface name (which `ip` treats as an alias
+/// separator) and, since every pulled image shares that same 7-character
+/// prefix, collapse the usable suffix down to one hex nibble of entropy.
+fn veth_names(id: &str) -> (String, String) {
+    let hex_id = id.strip_prefix("sha256:").unwrap_or(id);
+    let short = &hex_id[..hex_id.len().min(8)];
+    (format!("veth{}", short), format!("ceth{}", short))
+}
+
+/// Gives a just-forked container its own networking: a veth pair with the
+/// host leg plugged into `ace0` and the container leg moved into the
+/// netns of `child_pid` (referenced via `/proc/<pid>/ns/net`, since that's
+/// the only handle we have on a namespace we didn't create ourselves).
+///
+/// The caller (`Container::run`) only invokes this after the child has
+/// signalled over its own sync pipe that `unshare(CloneFlags::CLONE_NEWNET)`
+/// has completed, so the peer is guaranteed to land in the child's empty
+/// netns rather than racing whatever namespace it was in at fork time.
+/// It must also still be running in the host's own netns and mount
+/// namespace (see `Container::run`'s doc comment) for the `ip`/`nsenter`
+/// calls below to resolve at all; `host_uid` is the real host uid that
+/// authorizes the host-crossing ones (see `host_command`).
+pub fn setup_container_network(
+    id: &str,
+    child_pid: Pid,
+    opts: &NetworkOptions,
+    host_uid: Uid,
+) -> io::Result<()> {
+    let (host_if, peer_if) = veth_names(id);
+
+    let subnet = Subnet::parse(opts.subnet.as_deref().unwrap_or(DEFAULT_SUBNET))?;
+    let addr = opts
+        .ip
+        .clone()
+        .unwrap_or_else(|| format!("{}/{}", subnet.host_address(child_pid), subnet.prefix));
+    let gateway = subnet.gateway();
+
+    info!(
+        "[Host] container {} addressed {} via {} on {}",
+        id,
+        addr,
+        gateway,
+        subnet.cidr()
+    );
+
+    ip(
+        host_uid,
+        &["link", "add", &host_if, "type", "veth", "peer", "name", &peer_if],
+    )?;
+    ip(host_uid, &["link", "set", &host_if, "master", BRIDGE_NAME])?;
+    ip(host_uid, &["link", "set", &host_if, "up"])?;
+
+    ip(
+        host_uid,
+        &["link", "set", &peer_if, "netns", &child_pid.as_raw().to_string()],
+    )?;
+
+    ip_in_ns(child_pid, &["link", "set", "lo", "up"])?;
+    ip_in_ns(child_pid, &["link", "set", &peer_if, "name", "eth0"])?;
+    ip_in_ns(child_pid, &["addr", "add", &addr, "dev", "eth0"])?;
+    ip_in_ns(child_pid, &["link", "set", "eth0", "up"])?;
+    ip_in_ns(
+        child_pid,
+        &[
+            "route",
+            "add",
+            "default",
+            "via",
+            &gateway.to_string(),
+            "dev",
+            "eth0",
+        ],
+    )
+}
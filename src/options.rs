@@ -7,6 +7,14 @@ pub fn get_runner_options(args: Vec<String>) -> Result<Matches, Fail> {
 
     opts.optopt("n", "name", "set container name", "CONTAINER_NAME");
     opts.optopt("", "exec", "exec command", "COMMAND");
+    opts.optopt("", "ip", "pin the container's address", "ADDRESS/CIDR");
+    opts.optopt("", "subnet", "subnet to address the container from", "CIDR");
+    opts.optopt(
+        "",
+        "rootfs-tar",
+        "build the container from a local rootfs tarball",
+        "FILE",
+    );
     opts.optflag("h", "help", "print help message");
     opts.optflag("", "del", "delete container");
 